@@ -1,15 +1,17 @@
+use gdk_pixbuf::Pixbuf;
 use gtk::{
-    prelude::{ContainerExt, IconThemeExt, WidgetExt},
-    traits::{GtkMenuItemExt, MenuShellExt},
-    IconLookupFlags, Menu, MenuBar, MenuItem, Orientation, SeparatorMenuItem,
+    gdk,
+    prelude::{Cast, ContainerExt, IconThemeExt, StyleContextExt, WidgetExt},
+    traits::{CheckMenuItemExt, GtkMenuItemExt, MenuShellExt},
+    IconLookupFlags, Inhibit, Menu, MenuBar, MenuItem, Orientation, SeparatorMenuItem,
 };
 use once_cell::sync::Lazy;
 use std::{collections::HashMap, sync::Mutex, thread};
 use stray::{
     message::{
         menu::{MenuType, TrayMenu},
-        tray::StatusNotifierItem,
-        NotifierItemCommand, NotifierItemMessage,
+        tray::{IconPixmap, Status, StatusNotifierItem},
+        NotifierItemCommand, NotifierItemMessage, ScrollOrientation,
     },
     StatusNotifierWatcher,
 };
@@ -20,22 +22,57 @@ struct NotifierItem {
     menu: Option<TrayMenu>,
 }
 
+/// The GTK widgets making up a single tray item, kept around so an update can mutate them in
+/// place instead of tearing down and rebuilding the whole tray.
+struct NotifierItemWidgets {
+    menu_item: MenuItem,
+    icon: gtk::Image,
+    submenu: Option<Menu>,
+}
+
 pub struct StatusNotifierWrapper {
     menu: stray::message::menu::MenuItem,
 }
 
 static STATE: Lazy<Mutex<HashMap<String, NotifierItem>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static WIDGETS: Lazy<Mutex<HashMap<String, NotifierItemWidgets>>> = Lazy::new(|| Mutex::new(HashMap::new()));
 
 impl StatusNotifierWrapper {
     fn to_menu_item(self, sender: mpsc::Sender<NotifierItemCommand>, notifier_address: String, menu_path: String) -> MenuItem {
+        let is_toggle =
+            matches!(self.menu.menu_type, MenuType::Standard) && matches!(self.menu.toggle_type.as_str(), "checkmark" | "radio");
+
         let item: Box<dyn AsRef<MenuItem>> = match self.menu.menu_type {
             MenuType::Separator => Box::new(SeparatorMenuItem::new()),
+            MenuType::Standard if is_toggle => {
+                let check_item = gtk::CheckMenuItem::with_label(self.menu.label.as_str());
+                check_item.set_draw_as_radio(self.menu.toggle_type == "radio");
+                check_item.set_active(self.menu.toggle_state == 1);
+                Box::new(check_item)
+            }
             MenuType::Standard => Box::new(MenuItem::with_label(self.menu.label.as_str())),
         };
 
         let item = (*item).as_ref().clone();
 
-        {
+        if is_toggle {
+            let sender = sender.clone();
+            let notifier_address = notifier_address.clone();
+            let menu_path = menu_path.clone();
+
+            // toggle entries (checkbox/radio) report their clicks through "toggled" rather than "activate"
+            if let Ok(check_item) = item.clone().downcast::<gtk::CheckMenuItem>() {
+                check_item.connect_toggled(move |_check_item| {
+                    sender
+                        .try_send(NotifierItemCommand::MenuItemClicked {
+                            submenu_id: self.menu.id,
+                            menu_path: menu_path.clone(),
+                            notifier_address: notifier_address.clone(),
+                        })
+                        .unwrap();
+                });
+            }
+        } else {
             let sender = sender.clone();
             let notifier_address = notifier_address.clone();
             let menu_path = menu_path.clone();
@@ -66,32 +103,79 @@ impl StatusNotifierWrapper {
     }
 }
 
+/// SNI pixmaps are ARGB32 in network byte order, i.e. each pixel is the four bytes `[A, R, G, B]`.
+/// gdk_pixbuf wants `[R, G, B, A]`, which is just that same group of bytes rotated left by one.
+/// No real tray icon is anywhere close to this size; treating anything bigger as bogus keeps the
+/// `width * 4` rowstride computation below from overflowing on a malicious/buggy SNI client.
+const MAX_PIXMAP_DIMENSION: i32 = 4096;
+
+fn pixmap_to_pixbuf(pixmap: &IconPixmap, size: i32) -> Option<Pixbuf> {
+    if pixmap.width <= 0 || pixmap.height <= 0 || pixmap.width > MAX_PIXMAP_DIMENSION || pixmap.height > MAX_PIXMAP_DIMENSION {
+        log::warn!("Ignoring icon_pixmap with implausible dimensions {}x{}", pixmap.width, pixmap.height);
+        return None;
+    }
+
+    let rowstride = pixmap.width * 4;
+    if pixmap.pixels.len() < (rowstride as usize) * (pixmap.height as usize) {
+        log::warn!("Ignoring icon_pixmap with {} bytes, too short for a {}x{} ARGB32 image", pixmap.pixels.len(), pixmap.width, pixmap.height);
+        return None;
+    }
+
+    let mut pixels = pixmap.pixels.clone();
+    for argb in pixels.chunks_exact_mut(4) {
+        argb.rotate_left(1);
+    }
+
+    let pixbuf = Pixbuf::from_mut_slice(pixels, gdk_pixbuf::Colorspace::Rgb, true, 8, pixmap.width, pixmap.height, rowstride);
+    pixbuf.scale_simple(size, size, gdk_pixbuf::InterpType::Bilinear)
+}
+
 impl NotifierItem {
-    fn get_icon(&self) -> Option<gtk::Image> {
-        let icon_name = self.item.icon_name.as_ref().unwrap();
+    fn get_icon(&self) -> Option<Pixbuf> {
+        let icon_name = if let Status::NeedsAttention = self.item.status {
+            self.item.attention_icon_name.as_ref().filter(|name| !name.is_empty()).or(self.item.icon_name.as_ref())
+        } else {
+            self.item.icon_name.as_ref()
+        };
 
-        if let Some(path) = self.item.icon_theme_path.as_ref() && !path.is_empty() {
-            // custom icon path specified, look there
-            let theme = gtk::IconTheme::new();
-            theme.prepend_search_path(path);
+        if let Some(icon_name) = icon_name.filter(|name| !name.is_empty()) {
+            if let Some(path) = self.item.icon_theme_path.as_ref() && !path.is_empty() {
+                // custom icon path specified, look there
+                let theme = gtk::IconTheme::new();
+                theme.prepend_search_path(path);
 
+                match theme.load_icon(icon_name, 24, IconLookupFlags::FORCE_SIZE) {
+                    Err(e) => log::warn!("Could not find icon {:?} in path {:?}: {}", icon_name, path, e),
+                    Ok(Some(pb)) => return Some(pb),
+                    Ok(None) => {}
+                }
+            }
+
+            // try default theme
+            let theme = gtk::IconTheme::default().expect("Could not get default gtk theme");
             match theme.load_icon(icon_name, 24, IconLookupFlags::FORCE_SIZE) {
-                Err(e) => log::warn!("Could not find icon {:?} in path {:?}: {}", path, theme, e),
-                Ok(pb) => return Some(gtk::Image::from_pixbuf(pb.as_ref())),
+                Err(e) => log::warn!("Could not find icon {:?} in default theme: {}", icon_name, e),
+                Ok(Some(pb)) => return Some(pb),
+                Ok(None) => {}
             }
         }
 
-        // try default theme
-        let theme = gtk::IconTheme::default().expect("Could not get default gtk theme");
-        match theme.load_icon(icon_name, 24, IconLookupFlags::FORCE_SIZE) {
-            Err(e) => log::warn!("Could not find icon {:?} in default theme: {}", icon_name, e),
-            Ok(pb) => return Some(gtk::Image::from_pixbuf(pb.as_ref())),
+        // no themed icon name resolved; fall back to the raw ARGB pixmap the app shipped inline
+        let pixmap = if let Status::NeedsAttention = self.item.status {
+            self.item.attention_icon_pixmap.as_ref().filter(|pixmaps| !pixmaps.is_empty()).or(self.item.icon_pixmap.as_ref())
+        } else {
+            self.item.icon_pixmap.as_ref()
+        };
+
+        if let Some(pixbuf) = pixmap.and_then(|pixmaps| pixmaps.iter().max_by_key(|p| p.width)).and_then(|p| pixmap_to_pixbuf(p, 24)) {
+            return Some(pixbuf);
         }
 
         // still no icon, use fallback image
+        let theme = gtk::IconTheme::default().expect("Could not get default gtk theme");
         match theme.load_icon("image-missing", 24, IconLookupFlags::FORCE_SIZE) {
             Err(e) => log::error!("Could not find fallback icon \"image-missing\" in default theme: {}", e),
-            Ok(pb) => return Some(gtk::Image::from_pixbuf(pb.as_ref())),
+            Ok(pb) => return pb,
         }
 
         None
@@ -115,6 +199,150 @@ pub fn start_communication_thread(sender: mpsc::Sender<NotifierItemMessage>, cmd
     });
 }
 
+/// Build the submenu for `notifier_item`, or `None` if it doesn't have one.
+fn build_submenu(notifier_item: &NotifierItem, cmd_tx: &mpsc::Sender<NotifierItemCommand>, address: &str) -> Option<Menu> {
+    let tray_menu = notifier_item.menu.as_ref()?;
+    if tray_menu.submenus.is_empty() {
+        return None;
+    }
+
+    let menu_path = notifier_item.item.menu.as_ref().unwrap().to_string();
+    let menu = Menu::new();
+    for submenu in tray_menu.submenus.iter().cloned() {
+        let submenu_item = StatusNotifierWrapper { menu: submenu };
+        let menu_item = submenu_item.to_menu_item(cmd_tx.clone(), address.to_string(), menu_path.clone());
+        menu.append(&menu_item);
+    }
+    menu.show_all();
+    Some(menu)
+}
+
+/// Apply `notifier_item` to the widgets tracked for `address`, creating them on first sight and
+/// otherwise mutating only what changed so open submenus aren't disturbed.
+fn update_item(
+    v_box: &MenuBar,
+    widgets: &mut HashMap<String, NotifierItemWidgets>,
+    old: Option<&NotifierItem>,
+    notifier_item: &NotifierItem,
+    cmd_tx: &mpsc::Sender<NotifierItemCommand>,
+    address: &str,
+) {
+    if let Status::Passive = notifier_item.item.status {
+        // don't display; see documentation of Status
+        if let Some(widgets) = widgets.remove(address) {
+            v_box.remove(&widgets.menu_item);
+        }
+        return;
+    }
+
+    // widgets are dropped while an item is Passive, so a returning item needs everything built afresh
+    let is_new = !widgets.contains_key(address);
+    let icon_changed = is_new || old.map_or(true, |old| {
+        old.item.icon_name != notifier_item.item.icon_name
+            || old.item.icon_theme_path != notifier_item.item.icon_theme_path
+            || old.item.icon_pixmap != notifier_item.item.icon_pixmap
+            || old.item.attention_icon_name != notifier_item.item.attention_icon_name
+            || old.item.attention_icon_pixmap != notifier_item.item.attention_icon_pixmap
+            || old.item.status != notifier_item.item.status
+    });
+    let menu_changed = is_new || old.map_or(true, |old| old.menu != notifier_item.menu);
+
+    let entry = widgets.entry(address.to_string()).or_insert_with(|| {
+        let icon = gtk::Image::new();
+        let menu_item = MenuItem::new();
+        let menu_item_box = gtk::Box::new(Orientation::Horizontal, 3);
+        menu_item_box.add(&icon);
+        menu_item.add(&menu_item_box);
+        v_box.append(&menu_item);
+
+        menu_item.add_events(gdk::EventMask::BUTTON_PRESS_MASK | gdk::EventMask::SCROLL_MASK);
+
+        {
+            let cmd_tx = cmd_tx.clone();
+            let address = address.to_string();
+            menu_item.connect_button_press_event(move |_widget, event| {
+                let command = match event.button() {
+                    1 => Some(NotifierItemCommand::Activate { address: address.clone(), x: event.root().0 as i32, y: event.root().1 as i32 }),
+                    2 => Some(NotifierItemCommand::SecondaryActivate { address: address.clone(), x: event.root().0 as i32, y: event.root().1 as i32 }),
+                    _ => None,
+                };
+                if let Some(command) = command {
+                    if let Err(e) = cmd_tx.try_send(command) {
+                        log::warn!("Failed to send tray click command: {}", e);
+                    }
+                }
+                Inhibit(false)
+            });
+        }
+
+        {
+            let cmd_tx = cmd_tx.clone();
+            let address = address.to_string();
+            menu_item.connect_scroll_event(move |_widget, event| {
+                let (orientation, delta) = match event.direction() {
+                    gdk::ScrollDirection::Up => (ScrollOrientation::Vertical, -1),
+                    gdk::ScrollDirection::Down => (ScrollOrientation::Vertical, 1),
+                    gdk::ScrollDirection::Left => (ScrollOrientation::Horizontal, -1),
+                    gdk::ScrollDirection::Right => (ScrollOrientation::Horizontal, 1),
+                    gdk::ScrollDirection::Smooth => {
+                        // touchpads report continuous deltas here instead of discrete directions
+                        let (dx, dy) = event.delta();
+                        if dy.abs() >= dx.abs() {
+                            (ScrollOrientation::Vertical, dy.round() as i32)
+                        } else {
+                            (ScrollOrientation::Horizontal, dx.round() as i32)
+                        }
+                    }
+                    _ => (ScrollOrientation::Vertical, 0),
+                };
+
+                if delta != 0 {
+                    if let Err(e) = cmd_tx.try_send(NotifierItemCommand::Scroll { address: address.clone(), delta, orientation }) {
+                        log::warn!("Failed to send tray scroll command: {}", e);
+                    }
+                }
+                Inhibit(false)
+            });
+        }
+
+        NotifierItemWidgets { menu_item, icon, submenu: None }
+    });
+
+    if icon_changed {
+        if let Some(pixbuf) = notifier_item.get_icon() {
+            entry.icon.set_from_pixbuf(Some(&pixbuf));
+        }
+    }
+
+    // keep the tooltip current as applications change it, so hovering a tray icon reveals what it is
+    match notifier_item.item.tool_tip.as_ref().filter(|tool_tip| !tool_tip.title.is_empty() || !tool_tip.description.is_empty()) {
+        Some(tool_tip) if tool_tip.description.is_empty() => entry.menu_item.set_tooltip_text(Some(&tool_tip.title)),
+        Some(tool_tip) => entry.menu_item.set_tooltip_markup(Some(&format!(
+            "<b>{}</b>\n{}",
+            glib::markup_escape_text(&tool_tip.title),
+            glib::markup_escape_text(&tool_tip.description)
+        ))),
+        None => entry.menu_item.set_tooltip_text(None),
+    }
+
+    // let users style/animate attention items (e.g. an unread-message indicator) from eww's stylesheet
+    let style_context = entry.menu_item.style_context();
+    if let Status::NeedsAttention = notifier_item.item.status {
+        style_context.add_class("tray-attention");
+    } else {
+        style_context.remove_class("tray-attention");
+    }
+
+    // only rebuild the submenu when its contents actually changed, so an update to something else
+    // on this item (tooltip, attention icon, ...) doesn't collapse a submenu the user has open
+    if menu_changed {
+        entry.submenu = build_submenu(notifier_item, cmd_tx, address);
+        entry.menu_item.set_submenu(entry.submenu.as_ref());
+    }
+
+    entry.menu_item.show_all();
+}
+
 pub fn spawn_local_handler(
     v_box: MenuBar,
     mut receiver: mpsc::Receiver<NotifierItemMessage>,
@@ -124,58 +352,24 @@ pub fn spawn_local_handler(
     let future = async move {
         while let Some(item) = receiver.recv().await {
             let mut state = STATE.lock().unwrap();
+            let mut widgets = WIDGETS.lock().unwrap();
 
             match item {
-                NotifierItemMessage::Update { address: id, item, menu } => {
-                    state.insert(id, NotifierItem { item: *item, menu });
+                NotifierItemMessage::Update { address, item, menu } => {
+                    let notifier_item = NotifierItem { item: *item, menu };
+                    let old = state.get(&address);
+                    update_item(&v_box, &mut widgets, old, &notifier_item, &cmd_tx, &address);
+                    state.insert(address, notifier_item);
                 }
                 NotifierItemMessage::Remove { address } => {
+                    if let Some(widgets) = widgets.remove(&address) {
+                        v_box.remove(&widgets.menu_item);
+                    }
                     state.remove(&address);
                 }
             }
-
-            // FIXME don't recreate all icons on update, so menus don't get destroyed
-            for child in v_box.children() {
-                v_box.remove(&child);
-            }
-
-            for (address, notifier_item) in state.iter() {
-                if let Status::Passive = notifier_item.item.status {
-                    continue // don't display; see documentation of Status
-                }
-
-                if let Some(icon) = notifier_item.get_icon() {
-                    // Create the menu
-
-                    let menu_item = MenuItem::new();
-                    let menu_item_box = gtk::Box::new(Orientation::Horizontal, 3);
-                    menu_item_box.add(&icon);
-                    menu_item.add(&menu_item_box);
-
-                    if let Some(tray_menu) = &notifier_item.menu {
-                        let menu = Menu::new();
-                        tray_menu
-                            .submenus
-                            .iter()
-                            .map(|submenu| StatusNotifierWrapper { menu: submenu.to_owned() })
-                            .map(|item| {
-                                let menu_path = notifier_item.item.menu.as_ref().unwrap().to_string();
-                                let address = address.to_string();
-                                item.to_menu_item(cmd_tx.clone(), address, menu_path)
-                            })
-                            .for_each(|item| menu.append(&item));
-
-                        if !tray_menu.submenus.is_empty() {
-                            menu_item.set_submenu(Some(&menu));
-                        }
-                    }
-                    v_box.append(&menu_item);
-                };
-
-                v_box.show_all();
-            }
         }
     };
 
     main_context.spawn_local(future);
-}
\ No newline at end of file
+}